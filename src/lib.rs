@@ -4,11 +4,96 @@
 //! It does not rely on the standard library, and can be used in `no_std` environments.
 #![no_std]
 
+extern crate alloc;
+
 #[cfg(test)]
 extern crate std;
 
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
 use core::str;
 
+/// The error returned by [`try_with_str_bytes`] when a callback leaves behind invalid UTF-8.
+///
+/// This mirrors the surface of [`str::from_utf8`]'s `Utf8Error`, exposing [`valid_up_to`] and
+/// [`error_len`] so callers can see exactly where corruption began.
+///
+/// [`valid_up_to`]: WithStrBytesError::valid_up_to
+/// [`error_len`]: WithStrBytesError::error_len
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WithStrBytesError(str::Utf8Error);
+
+impl WithStrBytesError {
+    /// Returns the index in the byte slice up to which the contents were valid UTF-8.
+    #[must_use]
+    pub fn valid_up_to(&self) -> usize {
+        self.0.valid_up_to()
+    }
+
+    /// Returns the length of the invalid byte sequence, if it could be determined.
+    ///
+    /// See [`str::Utf8Error::error_len`] for the cases in which this is `None`.
+    #[must_use]
+    pub fn error_len(&self) -> Option<usize> {
+        self.0.error_len()
+    }
+}
+
+impl fmt::Display for WithStrBytesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl core::error::Error for WithStrBytesError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Zeroes every byte in `bytes`. Used to recover from a callback unwinding with the bytes in an
+/// unknown state.
+fn zero_fill(bytes: &mut [u8]) {
+    for byte in bytes {
+        *byte = 0;
+    }
+}
+
+/// Revalidates `bytes` as UTF-8, filling everything from the first invalid byte onward with
+/// `fill` so that `bytes` is always left holding valid UTF-8. Returns the error describing where
+/// corruption began, if any.
+fn fill_invalid_tail(bytes: &mut [u8], fill: u8) -> Result<(), WithStrBytesError> {
+    match str::from_utf8(bytes) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            for byte in &mut bytes[e.valid_up_to()..] {
+                *byte = fill;
+            }
+            Err(WithStrBytesError(e))
+        }
+    }
+}
+
+/// A policy for recovering a string's validity when a [`with_str_bytes_recover`] callback leaves
+/// it as invalid UTF-8.
+///
+/// Every variant preserves the bytes up to the point of the first invalid UTF-8 byte; they differ
+/// only in what happens to the bytes from that point onward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// Zero-fill the invalid tail, then panic. This is the behavior of [`with_str_bytes`].
+    Panic,
+    /// Zero-fill the invalid tail and return normally.
+    ZeroFill,
+    /// Fill the invalid tail with the given byte and return normally.
+    ///
+    /// The byte must be valid single-byte UTF-8, i.e. ASCII (`< 0x80`); this is checked by
+    /// [`with_str_bytes_recover`] before the callback runs.
+    FillWith(u8),
+}
+
 /// Executes a function on the bytes of a string, asserting that it is valid UTF-8.
 ///
 /// # Panics
@@ -20,6 +105,9 @@ use core::str;
 /// If the callback itself panics, the entire string's contents is unspecified, but it will be
 /// valid UTF-8. Even if the byte slice was set to invalid UTF-8, there will not be a double panic.
 ///
+/// See [`try_with_str_bytes`] for a variant that returns an error instead of panicking, and
+/// [`with_str_bytes_recover`] for a variant that lets the caller pick a different recovery policy.
+///
 /// # Examples
 ///
 /// Replace all spaces in a string with dashes in-place:
@@ -39,6 +127,47 @@ pub fn with_str_bytes<R, F>(s: &mut str, f: F) -> R
 where
     F: FnOnce(&mut [u8]) -> R,
 {
+    with_str_bytes_recover(s, Recovery::Panic, f)
+}
+
+/// Executes a function on the bytes of a string, recovering its validity according to `recovery`
+/// if the function leaves it as invalid UTF-8.
+///
+/// This keeps the safe-by-construction invariant that `s` is always valid UTF-8 on return, while
+/// letting the caller pick a recovery policy that fits their data instead of the hardcoded
+/// zero-fill-then-panic of [`with_str_bytes`]. Indeed, `with_str_bytes` is defined as
+/// `with_str_bytes_recover(s, Recovery::Panic, f)`.
+///
+/// If the callback itself panics, the entire string's contents is unspecified, but it will be
+/// valid UTF-8, regardless of `recovery`. Even if the byte slice was set to invalid UTF-8, there
+/// will not be a double panic.
+///
+/// # Panics
+///
+/// This panics if `recovery` is [`Recovery::FillWith`] with a byte that is not ASCII.
+///
+/// This also panics if `recovery` is [`Recovery::Panic`] and the function causes the string to
+/// become invalid UTF-8; see [`with_str_bytes`] for details.
+///
+/// # Examples
+///
+/// ```
+/// use with_str_bytes::Recovery;
+///
+/// let mut data: Box<str> = Box::from("abc");
+/// with_str_bytes::with_str_bytes_recover(&mut data, Recovery::FillWith(b' '), |bytes| {
+///     bytes[1] = 0xC0;
+/// });
+/// assert_eq!(&*data, "a  ");
+/// ```
+pub fn with_str_bytes_recover<R, F>(s: &mut str, recovery: Recovery, f: F) -> R
+where
+    F: FnOnce(&mut [u8]) -> R,
+{
+    if let Recovery::FillWith(byte) = recovery {
+        assert!(byte.is_ascii(), "fill byte must be valid single-byte UTF-8 (ASCII)");
+    }
+
     struct Guard<'a> {
         bytes: &'a mut [u8],
         panicking: bool,
@@ -46,14 +175,7 @@ where
     impl Drop for Guard<'_> {
         fn drop(&mut self) {
             if self.panicking {
-                for byte in &mut *self.bytes {
-                    *byte = 0;
-                }
-            } else if let Err(e) = str::from_utf8(self.bytes) {
-                for byte in &mut self.bytes[e.valid_up_to()..] {
-                    *byte = 0;
-                }
-                panic!("`with_bytes` encountered invalid utf-8: {}", e);
+                zero_fill(self.bytes);
             }
         }
     }
@@ -62,18 +184,287 @@ where
         bytes: unsafe { s.as_bytes_mut() },
         panicking: true,
     };
-    let ret = f(&mut guard.bytes);
+    let ret = f(guard.bytes);
+    guard.panicking = false;
+
+    let fill = match recovery {
+        Recovery::FillWith(byte) => byte,
+        Recovery::Panic | Recovery::ZeroFill => 0,
+    };
+    if let Err(e) = fill_invalid_tail(guard.bytes, fill) {
+        if let Recovery::Panic = recovery {
+            panic!("`with_bytes` encountered invalid utf-8: {}", e);
+        }
+    }
+    ret
+}
+
+/// Executes a function on the bytes of a string, returning an error instead of panicking if it
+/// causes the string to become invalid UTF-8.
+///
+/// # Errors
+///
+/// Returns an error if the function causes the string to become invalid UTF-8. In this case, the
+/// bytes from [`WithStrBytesError::valid_up_to`] onward are zeroed so the string is always left
+/// as valid UTF-8, and the error is returned rather than a panic being raised.
+///
+/// # Panics
+///
+/// If the callback itself panics, the entire string's contents is unspecified, but it will be
+/// valid UTF-8. Even if the byte slice was set to invalid UTF-8, there will not be a double panic.
+///
+/// # Examples
+///
+/// ```
+/// let mut data: Box<str> = Box::from("abc");
+/// let result = with_str_bytes::try_with_str_bytes(&mut data, |bytes| {
+///     bytes[1] = 0xC0;
+/// });
+/// assert!(result.is_err());
+/// assert_eq!(&*data, "a\0\0");
+/// ```
+pub fn try_with_str_bytes<R, F>(s: &mut str, f: F) -> Result<R, WithStrBytesError>
+where
+    F: FnOnce(&mut [u8]) -> R,
+{
+    struct Guard<'a> {
+        bytes: &'a mut [u8],
+        panicking: bool,
+    }
+    impl Drop for Guard<'_> {
+        fn drop(&mut self) {
+            if self.panicking {
+                zero_fill(self.bytes);
+            }
+        }
+    }
+
+    let mut guard = Guard {
+        bytes: unsafe { s.as_bytes_mut() },
+        panicking: true,
+    };
+    let ret = f(guard.bytes);
+    guard.panicking = false;
+    fill_invalid_tail(guard.bytes, 0).map(|()| ret)
+}
+
+/// Executes a function on a byte range of a string, asserting that the range remains valid UTF-8.
+///
+/// Unlike [`with_str_bytes`], which revalidates the entire string, this only revalidates `range`
+/// afterward, turning the cost of an edit from `O(len)` into `O(range.len())`. This is sound
+/// because the bytes outside of `range` are known to be valid going in and are never exposed to
+/// the callback, so they cannot be affected by it.
+///
+/// # Panics
+///
+/// This panics if `range.start` or `range.end` does not lie on a UTF-8 char boundary of `s`, for
+/// the same reason indexing a `str` with a non-boundary range panics.
+///
+/// This also panics if the function causes the bytes in `range` to become invalid UTF-8. In this
+/// case, the bytes up to the point of the first invalid UTF-8 byte will remain the same, and the
+/// contents of the rest of `range` is unspecified, although it will be valid UTF-8. The panic
+/// message first gives the absolute index of the first invalid byte, i.e. relative to `s`; the
+/// UTF-8 error quoted after it gives its index relative to `range`.
+///
+/// If the callback itself panics, the contents of `range` is unspecified, but it will be valid
+/// UTF-8. Even if the byte slice was set to invalid UTF-8, there will not be a double panic.
+///
+/// # Examples
+///
+/// ```
+/// let mut data: Box<str> = Box::from("Lorem ipsum");
+/// with_str_bytes::with_str_bytes_range(&mut data, 6..11, |bytes| {
+///     bytes.reverse();
+/// });
+/// assert_eq!(&*data, "Lorem muspi");
+/// ```
+pub fn with_str_bytes_range<R, F>(s: &mut str, range: Range<usize>, f: F) -> R
+where
+    F: FnOnce(&mut [u8]) -> R,
+{
+    assert!(
+        s.is_char_boundary(range.start),
+        "range start not on a char boundary",
+    );
+    assert!(
+        s.is_char_boundary(range.end),
+        "range end not on a char boundary",
+    );
+
+    struct Guard<'a> {
+        bytes: &'a mut [u8],
+        panicking: bool,
+    }
+    impl Drop for Guard<'_> {
+        fn drop(&mut self) {
+            if self.panicking {
+                zero_fill(self.bytes);
+            }
+        }
+    }
+
+    let start = range.start;
+    let bytes = unsafe { s.as_bytes_mut() };
+    let mut guard = Guard {
+        bytes: &mut bytes[range],
+        panicking: true,
+    };
+    let ret = f(guard.bytes);
+    guard.panicking = false;
+    if let Err(e) = fill_invalid_tail(guard.bytes, 0) {
+        panic!(
+            "`with_str_bytes_range` encountered invalid utf-8 at index {} (within `s`): {}",
+            start + e.valid_up_to(),
+            e,
+        );
+    }
+    ret
+}
+
+/// Executes a function on the byte vector backing a string, allowing it to grow or shrink.
+///
+/// Unlike [`with_str_bytes`], which only ever gets a fixed-length `&mut [u8]`, this hands the
+/// callback the underlying `&mut Vec<u8>`, the safe analogue of [`String::as_mut_vec`]. This
+/// allows operations that change the string's byte length, such as in-place UTF-8 normalization,
+/// escaping, or filtering.
+///
+/// If the function causes the string to become invalid UTF-8, the vector is truncated to the
+/// bytes up to the point of the first invalid UTF-8 byte, and this returns normally without
+/// panicking.
+///
+/// # Panics
+///
+/// If the callback itself panics, the vector is truncated to empty, but it will remain valid
+/// UTF-8. Even if the vector was left holding invalid UTF-8, there will not be a double panic.
+///
+/// # Examples
+///
+/// Filter out all spaces in a string in-place:
+///
+/// ```
+/// let mut data = String::from("Lorem ipsum dolor sit amet");
+/// with_str_bytes::with_string_bytes(&mut data, |bytes| {
+///     bytes.retain(|&byte| byte != b' ');
+/// });
+/// assert_eq!(data, "Loremipsumdolorsitamet");
+/// ```
+pub fn with_string_bytes<R, F>(s: &mut String, f: F) -> R
+where
+    F: FnOnce(&mut Vec<u8>) -> R,
+{
+    struct Guard<'a> {
+        bytes: &'a mut Vec<u8>,
+        panicking: bool,
+    }
+    impl Drop for Guard<'_> {
+        fn drop(&mut self) {
+            if self.panicking {
+                self.bytes.clear();
+            } else if let Err(e) = str::from_utf8(self.bytes) {
+                self.bytes.truncate(e.valid_up_to());
+            }
+        }
+    }
+
+    let mut guard = Guard {
+        bytes: unsafe { s.as_mut_vec() },
+        panicking: true,
+    };
+    let ret = f(guard.bytes);
+    guard.panicking = false;
+    ret
+}
+
+/// Executes a function on the byte vector backing a string, repairing invalid UTF-8 by
+/// substituting the Unicode replacement character (U+FFFD) instead of discarding data.
+///
+/// This is like [`with_string_bytes`], but where that truncates the string at the first sign of
+/// invalid UTF-8, this walks the whole buffer, copying valid runs as-is and inserting one U+FFFD
+/// for each maximal invalid byte sequence, much like [`String::from_utf8_lossy`]. The result is
+/// always valid UTF-8.
+///
+/// # Panics
+///
+/// If the callback itself panics, the vector is truncated to empty, but it will remain valid
+/// UTF-8. There will not be a double panic.
+///
+/// # Examples
+///
+/// ```
+/// let mut data = String::from("abc");
+/// with_str_bytes::with_string_bytes_lossy(&mut data, |bytes| {
+///     bytes[1] = 0xC0;
+/// });
+/// assert_eq!(data, "a\u{FFFD}c");
+/// ```
+pub fn with_string_bytes_lossy<R, F>(s: &mut String, f: F) -> R
+where
+    F: FnOnce(&mut Vec<u8>) -> R,
+{
+    struct Guard<'a> {
+        bytes: &'a mut Vec<u8>,
+        panicking: bool,
+    }
+    impl Drop for Guard<'_> {
+        fn drop(&mut self) {
+            if self.panicking {
+                self.bytes.clear();
+            } else {
+                repair_lossy(self.bytes);
+            }
+        }
+    }
+
+    let mut guard = Guard {
+        bytes: unsafe { s.as_mut_vec() },
+        panicking: true,
+    };
+    let ret = f(guard.bytes);
     guard.panicking = false;
     ret
 }
 
+/// Rewrites `bytes` in place so that it holds valid UTF-8, replacing each maximal invalid byte
+/// sequence with a single U+FFFD replacement character.
+fn repair_lossy(bytes: &mut Vec<u8>) {
+    if str::from_utf8(bytes).is_ok() {
+        return;
+    }
+
+    const REPLACEMENT: &[u8] = "\u{FFFD}".as_bytes();
+
+    let mut repaired = Vec::with_capacity(bytes.len());
+    let mut rest = &bytes[..];
+    loop {
+        match str::from_utf8(rest) {
+            Ok(valid) => {
+                repaired.extend_from_slice(valid.as_bytes());
+                break;
+            }
+            Err(e) => {
+                repaired.extend_from_slice(&rest[..e.valid_up_to()]);
+                repaired.extend_from_slice(REPLACEMENT);
+                match e.error_len() {
+                    Some(len) => rest = &rest[e.valid_up_to() + len..],
+                    None => break,
+                }
+            }
+        }
+    }
+    *bytes = repaired;
+}
+
 #[cfg(test)]
 mod tests {
     use std::boxed::Box;
+    use std::error::Error;
     use std::panic::{self, AssertUnwindSafe};
-    use std::string::String;
+    use std::string::{String, ToString};
 
-    use super::with_str_bytes;
+    use super::{
+        try_with_str_bytes, with_str_bytes, with_str_bytes_range, with_str_bytes_recover,
+        with_string_bytes, with_string_bytes_lossy, Recovery,
+    };
 
     #[test]
     fn empty() {
@@ -129,4 +520,226 @@ mod tests {
 
         assert_eq!(&*data, "\0\0\0");
     }
+
+    #[test]
+    fn try_valid_utf8() {
+        let mut data: Box<str> = Box::from("---");
+
+        let ret = try_with_str_bytes(&mut data, |bytes| {
+            bytes.copy_from_slice(b"abc");
+            5
+        })
+        .unwrap();
+
+        assert_eq!(ret, 5);
+        assert_eq!(&*data, "abc");
+    }
+
+    #[test]
+    fn try_invalid_utf8() {
+        let mut data: Box<str> = Box::from("abc");
+
+        let err = try_with_str_bytes(&mut data, |bytes| {
+            bytes[1] = 0xC0;
+        })
+        .unwrap_err();
+
+        assert_eq!(err.valid_up_to(), 1);
+        assert_eq!(err.error_len(), Some(1));
+        assert_eq!(err.to_string(), "invalid utf-8 sequence of 1 bytes from index 1");
+        assert!(Error::source(&err).is_some());
+
+        assert_eq!(&*data, "a\0\0");
+    }
+
+    #[test]
+    fn string_grow() {
+        let mut data = String::from("abc");
+
+        with_string_bytes(&mut data, |bytes| {
+            bytes.extend_from_slice(b"def");
+        });
+
+        assert_eq!(data, "abcdef");
+    }
+
+    #[test]
+    fn string_shrink() {
+        let mut data = String::from("abcdef");
+
+        with_string_bytes(&mut data, |bytes| {
+            bytes.truncate(3);
+        });
+
+        assert_eq!(data, "abc");
+    }
+
+    #[test]
+    fn string_invalid_utf8() {
+        let mut data = String::from("abc");
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            with_string_bytes(&mut data, |bytes| {
+                bytes.push(0xC0);
+            });
+        }))
+        .unwrap();
+
+        assert_eq!(data, "abc");
+    }
+
+    #[test]
+    fn string_panics() {
+        let mut data = String::from("abc");
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            with_string_bytes(&mut data, |_| panic!("Oh no"));
+        }))
+        .unwrap_err();
+
+        assert_eq!(data, "");
+    }
+
+    #[test]
+    fn lossy_valid_utf8() {
+        let mut data = String::from("abc");
+
+        with_string_bytes_lossy(&mut data, |bytes| {
+            bytes.extend_from_slice(b"def");
+        });
+
+        assert_eq!(data, "abcdef");
+    }
+
+    #[test]
+    fn lossy_invalid_utf8() {
+        let mut data = String::from("abc");
+
+        with_string_bytes_lossy(&mut data, |bytes| {
+            bytes[1] = 0xC0;
+        });
+
+        assert_eq!(data, "a\u{FFFD}c");
+    }
+
+    #[test]
+    fn lossy_truncated_sequence_at_end() {
+        let mut data = String::from("abc");
+
+        with_string_bytes_lossy(&mut data, |bytes| {
+            bytes.push(0xE2);
+            bytes.push(0x82);
+        });
+
+        assert_eq!(data, "abc\u{FFFD}");
+    }
+
+    #[test]
+    fn lossy_panics() {
+        let mut data = String::from("abc");
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            with_string_bytes_lossy(&mut data, |_| panic!("Oh no"));
+        }))
+        .unwrap_err();
+
+        assert_eq!(data, "");
+    }
+
+    #[test]
+    fn range_valid_utf8() {
+        let mut data: Box<str> = Box::from("Lorem ipsum");
+
+        with_str_bytes_range(&mut data, 6..11, |bytes| {
+            bytes.reverse();
+        });
+
+        assert_eq!(&*data, "Lorem muspi");
+    }
+
+    #[test]
+    fn range_invalid_utf8() {
+        let mut data: Box<str> = Box::from("Lorem ipsum");
+
+        let msg = *panic::catch_unwind(AssertUnwindSafe(|| {
+            with_str_bytes_range(&mut data, 6..11, |bytes| {
+                bytes[0] = 0xC0;
+            });
+        }))
+        .unwrap_err()
+        .downcast::<String>()
+        .unwrap();
+
+        assert_eq!(
+            msg,
+            "`with_str_bytes_range` encountered invalid utf-8 at index 6 (within `s`): \
+             invalid utf-8 sequence of 1 bytes from index 0",
+        );
+
+        assert_eq!(&*data, "Lorem \0\0\0\0\0");
+    }
+
+    #[test]
+    #[should_panic(expected = "range start not on a char boundary")]
+    fn range_bad_start_boundary() {
+        let mut data: Box<str> = Box::from("\u{FFFD}bc");
+
+        with_str_bytes_range(&mut data, 1..3, |_| {});
+    }
+
+    #[test]
+    #[should_panic(expected = "range end not on a char boundary")]
+    fn range_bad_end_boundary() {
+        let mut data: Box<str> = Box::from("a\u{FFFD}c");
+
+        with_str_bytes_range(&mut data, 0..2, |_| {});
+    }
+
+    #[test]
+    fn recover_zero_fill() {
+        let mut data: Box<str> = Box::from("abc");
+
+        with_str_bytes_recover(&mut data, Recovery::ZeroFill, |bytes| {
+            bytes[1] = 0xC0;
+        });
+
+        assert_eq!(&*data, "a\0\0");
+    }
+
+    #[test]
+    fn recover_fill_with() {
+        let mut data: Box<str> = Box::from("abc");
+
+        with_str_bytes_recover(&mut data, Recovery::FillWith(b' '), |bytes| {
+            bytes[1] = 0xC0;
+        });
+
+        assert_eq!(&*data, "a  ");
+    }
+
+    #[test]
+    #[should_panic(expected = "fill byte must be valid single-byte UTF-8 (ASCII)")]
+    fn recover_fill_with_rejects_non_ascii() {
+        let mut data: Box<str> = Box::from("abc");
+
+        with_str_bytes_recover(&mut data, Recovery::FillWith(0x80), |_| {});
+    }
+
+    #[test]
+    fn recover_panic_matches_with_str_bytes() {
+        let mut data: Box<str> = Box::from("abc");
+
+        let msg = *panic::catch_unwind(AssertUnwindSafe(|| {
+            with_str_bytes_recover(&mut data, Recovery::Panic, |bytes| {
+                bytes[1] = 0xC0;
+            });
+        }))
+        .unwrap_err()
+        .downcast::<String>()
+        .unwrap();
+
+        assert_eq!(msg, "`with_bytes` encountered invalid utf-8: invalid utf-8 sequence of 1 bytes from index 1");
+
+        assert_eq!(&*data, "a\0\0");
+    }
 }